@@ -17,6 +17,7 @@ use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use core::num::NonZeroU64;
 use core::ops::{Deref, DerefMut, Index, IndexMut};
+use core::pin::Pin;
 use core::sync::atomic::{AtomicU32, Ordering};
 use core::{mem, ptr, slice};
 
@@ -25,6 +26,186 @@ use crate::debug::*;
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::Object;
 
+/// Provenance tracking for [`GpuWeakPointer`], compiled in only for
+/// `CONFIG_DRM_ASAHI_DEBUG` builds so release builds keep a bare VA with no
+/// extra bookkeeping.
+///
+/// Every live [`GpuObject`]/[`GpuOnlyArray`] registers the `(generation,
+/// type, len)` of its allocation here, keyed by base GPU VA. A
+/// [`GpuWeakPointer`] copies the generation of the object it was minted
+/// from, so a pointer that outlives its object (or is re-typed past the
+/// bounds of the original allocation) can be told apart from a pointer into
+/// whatever now lives at that VA.
+#[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+mod provenance {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use kernel::sync::smutex::Mutex;
+
+    static NEXT_GENERATION: AtomicU32 = AtomicU32::new(1);
+
+    pub(super) fn next_generation() -> u32 {
+        NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+    }
+
+    struct LiveRange {
+        generation: u32,
+        type_name: &'static str,
+        len: usize,
+    }
+
+    static LIVE_RANGES: Mutex<BTreeMap<u64, LiveRange>> = Mutex::new(BTreeMap::new());
+
+    pub(super) fn register(base_va: u64, generation: u32, type_name: &'static str, len: usize) {
+        LIVE_RANGES.lock().insert(
+            base_va,
+            LiveRange {
+                generation,
+                type_name,
+                len,
+            },
+        );
+    }
+
+    pub(super) fn unregister(base_va: u64) {
+        LIVE_RANGES.lock().remove(&base_va);
+    }
+
+    /// Returns `true` if `va` falls within any currently-registered live
+    /// allocation, regardless of generation or type.
+    pub(super) fn is_live(va: u64) -> bool {
+        let live_ranges = LIVE_RANGES.lock();
+        match live_ranges.range(..=va).next_back() {
+            Some((base, range)) => va < base + range.len as u64,
+            None => false,
+        }
+    }
+
+    /// Snapshot of the base VAs of all currently-registered live
+    /// allocations.
+    pub(super) fn live_vas() -> Vec<u64> {
+        let live_ranges = LIVE_RANGES.lock();
+        let mut vas = Vec::new();
+        for base in live_ranges.keys() {
+            if vas.try_push(*base).is_err() {
+                pr_err!("Trace: failed to snapshot live allocation VAs");
+                break;
+            }
+        }
+        vas
+    }
+
+    /// A raw pointer to a live object's [`Trace`] implementation, keyed by
+    /// its own GPU VA in [`TRACE_REGISTRY`] below. Wrapped so it can live in
+    /// a `Mutex`; `TRACE_REGISTRY`'s lock is what actually makes sharing it
+    /// across threads sound, the same way it already is for `LIVE_RANGES`.
+    struct TraceHandle(*const dyn Trace);
+    // SAFETY: only ever accessed through `TRACE_REGISTRY`'s lock.
+    unsafe impl Send for TraceHandle {}
+
+    static TRACE_REGISTRY: Mutex<BTreeMap<u64, TraceHandle>> = Mutex::new(BTreeMap::new());
+
+    /// Registers `obj`'s [`Trace`] implementation under `va` (its own GPU
+    /// VA), so that discovering `va` while walking another object's edges
+    /// lets [`mark_and_report`] keep walking *through* it instead of
+    /// stopping there. `obj` must stay at the same address until
+    /// [`unregister_trace`] is called for `va`; [`GpuObject::register_trace`]
+    /// registers the `GpuObject` itself and takes `Pin<&Self>` for that
+    /// reason.
+    pub(super) fn register_trace(va: u64, obj: &dyn Trace) {
+        TRACE_REGISTRY.lock().insert(va, TraceHandle(obj as *const dyn Trace));
+    }
+
+    pub(super) fn unregister_trace(va: u64) {
+        TRACE_REGISTRY.lock().remove(&va);
+    }
+
+    /// Looks up the [`Trace`] handle registered for `va`, if any, and calls
+    /// `visitor` with it.
+    ///
+    /// Holds `TRACE_REGISTRY`'s lock for the duration of `visitor`, so a
+    /// concurrent `unregister_trace(va)` (as run from `Drop`, right before
+    /// the traced object is freed) blocks until `visitor` returns instead of
+    /// racing the dereference of the raw pointer behind it.
+    pub(super) fn with_trace(va: u64, visitor: impl FnOnce(&dyn Trace)) {
+        let registry = TRACE_REGISTRY.lock();
+        if let Some(handle) = registry.get(&va) {
+            visitor(unsafe { &*handle.0 });
+        }
+    }
+
+    /// Returns `true` if `va` falls within a still-live allocation that was
+    /// assigned `generation` and whose recorded type matches `type_name`.
+    pub(super) fn check(va: u64, generation: u32, type_name: &'static str) -> bool {
+        let live_ranges = LIVE_RANGES.lock();
+        match live_ranges.range(..=va).next_back() {
+            Some((base, range)) => {
+                range.generation == generation
+                    && type_names_match(range.type_name, type_name)
+                    && va < base + range.len as u64
+            }
+            None => false,
+        }
+    }
+
+    /// A `GpuOnlyArray<T, _>` registers its provenance once, under the
+    /// *element* type `T`, but hands out both per-item `GpuWeakPointer<T>`
+    /// and whole-array `GpuWeakPointer<[T]>` pointers. Both must validate
+    /// against that same registration, so treat a queried `[T]` type name as
+    /// matching a registered `T` one.
+    fn type_names_match(registered: &'static str, queried: &'static str) -> bool {
+        if registered == queried {
+            return true;
+        }
+        match queried.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            Some(inner) => inner == registered,
+            None => false,
+        }
+    }
+}
+
+#[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+fn new_generation() -> u32 {
+    provenance::next_generation()
+}
+
+#[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+fn new_generation() -> u32 {
+    0
+}
+
+#[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+fn register_provenance(base_va: u64, generation: u32, type_name: &'static str, len: usize) {
+    provenance::register(base_va, generation, type_name, len);
+}
+
+#[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+fn register_provenance(_base_va: u64, _generation: u32, _type_name: &'static str, _len: usize) {}
+
+#[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+fn unregister_provenance(base_va: u64) {
+    provenance::unregister(base_va);
+}
+
+#[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+fn unregister_provenance(_base_va: u64) {}
+
+#[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+fn register_trace(va: u64, obj: &dyn Trace) {
+    provenance::register_trace(va, obj);
+}
+
+#[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+fn register_trace(_va: u64, _obj: &dyn Trace) {}
+
+#[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+fn unregister_trace(va: u64) {
+    provenance::unregister_trace(va);
+}
+
+#[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+fn unregister_trace(_va: u64) {}
+
 #[repr(C, packed(4))]
 pub(crate) struct GpuPointer<'a, T: ?Sized>(NonZeroU64, PhantomData<&'a T>);
 
@@ -50,7 +231,12 @@ impl<'a, T: ?Sized> fmt::Debug for GpuPointer<'a, T> {
 }
 
 #[repr(C, packed(4))]
-pub(crate) struct GpuWeakPointer<T: ?Sized>(NonZeroU64, PhantomData<*const T>);
+pub(crate) struct GpuWeakPointer<T: ?Sized> {
+    addr: NonZeroU64,
+    #[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+    generation: u32,
+    _p: PhantomData<*const T>,
+}
 
 impl<T: ?Sized> Copy for GpuWeakPointer<T> {}
 
@@ -61,22 +247,76 @@ impl<T: ?Sized> Clone for GpuWeakPointer<T> {
 }
 
 impl<T: ?Sized> GpuWeakPointer<T> {
+    #[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+    pub(crate) fn new(addr: NonZeroU64, generation: u32) -> GpuWeakPointer<T> {
+        GpuWeakPointer {
+            addr,
+            generation,
+            _p: PhantomData,
+        }
+    }
+
+    #[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+    pub(crate) fn new(addr: NonZeroU64, _generation: u32) -> GpuWeakPointer<T> {
+        GpuWeakPointer {
+            addr,
+            _p: PhantomData,
+        }
+    }
+
+    #[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+    fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    #[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+    fn generation(&self) -> u32 {
+        0
+    }
+
     pub(crate) fn or(&self, other: u64) -> GpuWeakPointer<T> {
-        GpuWeakPointer(self.0 | other, PhantomData)
+        GpuWeakPointer::new(self.addr | other, self.generation())
     }
 
     // The third argument is a type inference hack
     pub(crate) unsafe fn offset<U>(&self, off: usize, _: *const U) -> GpuWeakPointer<U> {
-        GpuWeakPointer::<U>(
-            NonZeroU64::new(self.0.get() + (off as u64)).unwrap(),
-            PhantomData,
+        GpuWeakPointer::<U>::new(
+            NonZeroU64::new(self.addr.get() + (off as u64)).unwrap(),
+            self.generation(),
         )
     }
+
+    /// Checks that this pointer's generation and type still match a live,
+    /// registered allocation. A no-op that always succeeds outside
+    /// `CONFIG_DRM_ASAHI_DEBUG` builds.
+    #[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+    pub(crate) fn validate(&self) {
+        let addr = self.addr.get();
+        if !provenance::check(addr, self.generation, core::any::type_name::<T>()) {
+            pr_err!(
+                "GpuWeakPointer<{}> @ {:#x} (generation {}) is dangling or type-confused!",
+                core::any::type_name::<T>(),
+                addr,
+                self.generation,
+            );
+        }
+    }
+
+    #[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+    #[inline(always)]
+    pub(crate) fn validate(&self) {}
+
+    /// Reports this pointer as a [`Trace`] edge: its raw GPU VA and the
+    /// `type_name` of its pointee. A convenience for `Trace` impls that
+    /// just need to forward their embedded weak pointers as-is.
+    pub(crate) fn trace(&self, visitor: &mut dyn FnMut(u64, &'static str)) {
+        visitor(self.addr.get(), core::any::type_name::<T>());
+    }
 }
 
 impl<T: ?Sized> fmt::Debug for GpuWeakPointer<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let val = self.0;
+        let val = self.addr;
         f.write_fmt(format_args!("{:#x} ({})", val, core::any::type_name::<T>()))
     }
 }
@@ -105,6 +345,7 @@ macro_rules! inner_weak_ptr {
         fn uninit_from<T: GpuStruct>(_: GpuWeakPointer<T>) -> core::mem::MaybeUninit<T::Raw<'static>> {
             core::mem::MaybeUninit::uninit()
         }
+        $gpuva.validate();
         let tmp = uninit_from($gpuva);
         let outer = tmp.as_ptr();
         let p: *const _ = unsafe { core::ptr::addr_of!((*outer).$($f)*) };
@@ -118,11 +359,60 @@ pub(crate) trait GpuStruct: 'static {
     type Raw<'a>: Sized;
 }
 
+/// Inline-or-boxed storage for a [`GpuObject`]'s CPU-side companion struct.
+///
+/// `T` is stored directly (no extra heap allocation) when it is small enough
+/// to be worth it; larger `T` fall back to a `Box` just like before. Either
+/// way this lives inline inside `GpuObject` itself, so `Deref`/`DerefMut`
+/// cost nothing extra to use.
+const GPU_OBJECT_INLINE_THRESHOLD: usize = 64;
+
+enum Storage<T> {
+    Inline(T),
+    Boxed(Box<T>),
+}
+
+impl<T> Storage<T> {
+    fn new(inner: T) -> Result<Storage<T>> {
+        if mem::size_of::<T>() <= GPU_OBJECT_INLINE_THRESHOLD {
+            Ok(Storage::Inline(inner))
+        } else {
+            Ok(Storage::Boxed(Box::try_new(inner)?))
+        }
+    }
+}
+
+impl<T> Deref for Storage<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Storage::Inline(inner) => inner,
+            Storage::Boxed(inner) => inner,
+        }
+    }
+}
+
+impl<T> DerefMut for Storage<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            Storage::Inline(inner) => inner,
+            Storage::Boxed(inner) => inner,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Storage<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
 pub(crate) struct GpuObject<T: GpuStruct, U: Allocation<T>> {
     raw: *mut T::Raw<'static>,
     alloc: U,
     gpu_ptr: GpuWeakPointer<T>,
-    inner: Box<T>,
+    inner: Storage<T>,
 }
 
 impl<T: GpuStruct, U: Allocation<T>> GpuObject<T, U> {
@@ -143,8 +433,10 @@ impl<T: GpuStruct, U: Allocation<T>> GpuObject<T, U> {
         if alloc.size() < size {
             return Err(ENOMEM);
         }
-        let gpu_ptr =
-            GpuWeakPointer::<T>(NonZeroU64::new(alloc.gpu_ptr()).ok_or(EINVAL)?, PhantomData);
+        let gpu_va = NonZeroU64::new(alloc.gpu_ptr()).ok_or(EINVAL)?;
+        let generation = new_generation();
+        let gpu_ptr = GpuWeakPointer::<T>::new(gpu_va, generation);
+        register_provenance(gpu_va.get(), generation, core::any::type_name::<T>(), size);
         mod_dev_dbg!(
             alloc.device(),
             "Allocating {} @ {:#x}",
@@ -161,20 +453,27 @@ impl<T: GpuStruct, U: Allocation<T>> GpuObject<T, U> {
             raw: p,
             gpu_ptr,
             alloc,
-            inner: Box::try_new(inner)?,
+            inner: Storage::new(inner)?,
         })
     }
 
-    pub(crate) fn new_boxed(
+    fn new_with_storage(
         alloc: U,
-        inner: Box<T>,
+        inner: Storage<T>,
         callback: impl for<'a> FnOnce(&'a T, *mut MaybeUninit<T::Raw<'a>>) -> Result<&'a mut T::Raw<'a>>,
     ) -> Result<Self> {
         if alloc.size() < mem::size_of::<T::Raw<'static>>() {
             return Err(ENOMEM);
         }
-        let gpu_ptr =
-            GpuWeakPointer::<T>(NonZeroU64::new(alloc.gpu_ptr()).ok_or(EINVAL)?, PhantomData);
+        let gpu_va = NonZeroU64::new(alloc.gpu_ptr()).ok_or(EINVAL)?;
+        let generation = new_generation();
+        let gpu_ptr = GpuWeakPointer::<T>::new(gpu_va, generation);
+        register_provenance(
+            gpu_va.get(),
+            generation,
+            core::any::type_name::<T>(),
+            mem::size_of::<T::Raw<'static>>(),
+        );
         mod_dev_dbg!(
             alloc.device(),
             "Allocating {} @ {:#x}",
@@ -199,12 +498,20 @@ impl<T: GpuStruct, U: Allocation<T>> GpuObject<T, U> {
         })
     }
 
+    pub(crate) fn new_boxed(
+        alloc: U,
+        inner: Box<T>,
+        callback: impl for<'a> FnOnce(&'a T, *mut MaybeUninit<T::Raw<'a>>) -> Result<&'a mut T::Raw<'a>>,
+    ) -> Result<Self> {
+        GpuObject::<T, U>::new_with_storage(alloc, Storage::Boxed(inner), callback)
+    }
+
     pub(crate) fn new_inplace(
         alloc: U,
         inner: T,
         callback: impl for<'a> FnOnce(&'a T, *mut MaybeUninit<T::Raw<'a>>) -> Result<&'a mut T::Raw<'a>>,
     ) -> Result<Self> {
-        GpuObject::<T, U>::new_boxed(alloc, Box::try_new(inner)?, callback)
+        GpuObject::<T, U>::new_with_storage(alloc, Storage::new(inner)?, callback)
     }
 
     pub(crate) fn new_prealloc(
@@ -215,14 +522,26 @@ impl<T: GpuStruct, U: Allocation<T>> GpuObject<T, U> {
         if alloc.size() < mem::size_of::<T::Raw<'static>>() {
             return Err(ENOMEM);
         }
-        let gpu_ptr =
-            GpuWeakPointer::<T>(NonZeroU64::new(alloc.gpu_ptr()).ok_or(EINVAL)?, PhantomData);
+        let gpu_va = NonZeroU64::new(alloc.gpu_ptr()).ok_or(EINVAL)?;
+        let generation = new_generation();
+        let gpu_ptr = GpuWeakPointer::<T>::new(gpu_va, generation);
+        register_provenance(
+            gpu_va.get(),
+            generation,
+            core::any::type_name::<T>(),
+            mem::size_of::<T::Raw<'static>>(),
+        );
         mod_dev_dbg!(
             alloc.device(),
             "Allocating {} @ {:#x}",
             core::any::type_name::<T>(),
             alloc.gpu_ptr()
         );
+        // `inner_cb` hands out `gpu_ptr` before `inner` exists, so the
+        // constructor it calls may stash away the address of whatever it
+        // eventually builds. Always box here, regardless of size, so that
+        // address stays stable for the lifetime of this `GpuObject` instead
+        // of depending on `Self` never moving.
         let inner = inner_cb(gpu_ptr)?;
         let p = alloc.ptr().ok_or(EINVAL)?.as_ptr() as *mut MaybeUninit<T::Raw<'_>>;
         let raw = raw_cb(&*inner, p)? as *mut _ as *mut MaybeUninit<T::Raw<'_>>;
@@ -238,20 +557,20 @@ impl<T: GpuStruct, U: Allocation<T>> GpuObject<T, U> {
             raw: p as *mut u8 as *mut T::Raw<'static>,
             gpu_ptr,
             alloc,
-            inner,
+            inner: Storage::Boxed(inner),
         })
     }
 
     pub(crate) fn gpu_va(&self) -> NonZeroU64 {
-        self.gpu_ptr.0
+        self.gpu_ptr.addr
     }
 
     pub(crate) fn gpu_pointer(&self) -> GpuPointer<'_, T> {
-        GpuPointer(self.gpu_ptr.0, PhantomData)
+        GpuPointer(self.gpu_ptr.addr, PhantomData)
     }
 
     pub(crate) fn weak_pointer(&self) -> GpuWeakPointer<T> {
-        GpuWeakPointer(self.gpu_ptr.0, PhantomData)
+        self.gpu_ptr
     }
 
     /* FIXME: unsound
@@ -282,6 +601,112 @@ impl<T: GpuStruct, U: Allocation<T>> GpuObject<T, U> {
 pub(crate) trait OpaqueGpuObject {}
 impl<T: GpuStruct, U: Allocation<T>> OpaqueGpuObject for GpuObject<T, U> {}
 
+/// Implemented by CPU-side `GpuStruct` inner types that embed
+/// [`GpuWeakPointer`]s into other firmware objects (e.g. a work queue
+/// pointing at its buffers, a context pointing at its page tables).
+///
+/// `trace` calls `visitor` once per embedded weak pointer with its raw GPU
+/// VA and the `type_name` of its pointee, letting [`mark_and_report`] walk
+/// the object graph without needing to know the concrete type.
+pub(crate) trait Trace {
+    fn trace(&self, visitor: &mut dyn FnMut(u64, &'static str));
+}
+
+impl<T: GpuStruct + Trace, U: Allocation<T>> Trace for GpuObject<T, U> {
+    fn trace(&self, visitor: &mut dyn FnMut(u64, &'static str)) {
+        self.inner.trace(visitor)
+    }
+}
+
+impl<T: GpuStruct + Trace, U: Allocation<T>> GpuObject<T, U> {
+    /// Registers this object's [`Trace`] implementation under its own GPU
+    /// VA, so that [`mark_and_report`] can look it back up while walking
+    /// another object's edges and keep walking *through* it, instead of
+    /// treating it as a leaf just because it's not one of the explicit
+    /// `roots`.
+    ///
+    /// The registry keeps a raw pointer to `self` that must stay valid
+    /// until [`unregister_trace`] runs from [`Drop`], so this takes
+    /// `Pin<&Self>` rather than `&self`: `self` itself (not just whatever
+    /// `self.inner` owns) must never move again after this call. This is
+    /// unrelated to whether `T` is stored [`Storage::Inline`] or
+    /// [`Storage::Boxed`] inside `self` — either way, pinning `self` pins
+    /// everything it contains, so both storage kinds register the same way.
+    pub(crate) fn register_trace(self: Pin<&Self>) {
+        let this = self.get_ref();
+        register_trace(this.gpu_va().get(), this);
+    }
+}
+
+/// Walks every [`Trace`] edge reachable from `roots`, following each newly
+/// discovered VA that has its own registered [`Trace`] implementation (see
+/// [`GpuObject::register_trace`]) to keep walking transitively through the
+/// object graph instead of stopping after one hop, cross-checking every
+/// traced VA against the live-allocation registry used for
+/// [`GpuWeakPointer`] provenance, and reports via `dev_warn!`:
+///
+/// - any traced GPU VA that does not correspond to a currently-registered
+///   live allocation (a dangling reference), and
+/// - any live allocation that was never reached while tracing (a leak).
+///
+/// A no-op outside `CONFIG_DRM_ASAHI_DEBUG` builds.
+#[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+pub(crate) fn mark_and_report(dev: &kernel::device::Device, roots: &[&dyn Trace]) {
+    use alloc::collections::BTreeSet;
+
+    let mut seen: BTreeSet<u64> = BTreeSet::new();
+    let mut worklist: Vec<(u64, &'static str)> = Vec::new();
+
+    for root in roots {
+        root.trace(&mut |va, type_name| {
+            let _ = worklist.try_push((va, type_name));
+        });
+    }
+
+    while let Some((va, type_name)) = worklist.pop() {
+        if !seen.insert(va) {
+            continue;
+        }
+        if !provenance::is_live(va) {
+            dev_warn!(
+                dev,
+                "Trace: dangling GPU VA {:#x} ({}), no live allocation backs it",
+                va,
+                type_name
+            );
+            continue;
+        }
+
+        // Keep walking transitively: if whatever lives at `va` also has a
+        // registered `Trace` impl, queue its outgoing edges too.
+        let mut edges: Vec<(u64, &'static str)> = Vec::new();
+        provenance::with_trace(va, |traceable| {
+            traceable.trace(&mut |edge_va, edge_type| {
+                let _ = edges.try_push((edge_va, edge_type));
+            });
+        });
+        for edge in edges {
+            if worklist.try_push(edge).is_err() {
+                pr_err!("Trace: failed to queue edge while walking object graph");
+                break;
+            }
+        }
+    }
+
+    for live_va in provenance::live_vas() {
+        if !seen.contains(&live_va) {
+            dev_warn!(
+                dev,
+                "Trace: allocation @ {:#x} is live but unreachable from the given roots (leak?)",
+                live_va
+            );
+        }
+    }
+}
+
+#[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+pub(crate) fn mark_and_report(_dev: &kernel::device::Device, _roots: &[&dyn Trace]) {}
+
 impl<T: GpuStruct, U: Allocation<T>> Deref for GpuObject<T, U> {
     type Target = T;
 
@@ -327,6 +752,8 @@ pub(crate) struct GpuOnlyArray<T: Sized, U: Allocation<T>> {
     len: usize,
     alloc: U,
     gpu_ptr: NonZeroU64,
+    #[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+    generation: u32,
     _p: PhantomData<T>,
 }
 
@@ -342,13 +769,27 @@ impl<T: Sized, U: Allocation<T>> GpuOnlyArray<T, U> {
         if alloc.size() < bytes {
             return Err(ENOMEM);
         }
+        let generation = new_generation();
+        register_provenance(gpu_ptr.get(), generation, core::any::type_name::<T>(), bytes);
         Ok(Self {
             len: count,
             alloc,
             gpu_ptr,
+            #[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+            generation,
             _p: PhantomData,
         })
     }
+
+    #[cfg(CONFIG_DRM_ASAHI_DEBUG)]
+    fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    #[cfg(not(CONFIG_DRM_ASAHI_DEBUG))]
+    fn generation(&self) -> u32 {
+        0
+    }
 }
 
 impl<T: Sized + Copy, U: Allocation<T>> GpuArray<T, U> {
@@ -393,7 +834,7 @@ impl<T: Sized, U: Allocation<T>> GpuOnlyArray<T, U> {
     }
 
     pub(crate) fn weak_pointer(&self) -> GpuWeakPointer<[T]> {
-        GpuWeakPointer(self.gpu_ptr, PhantomData)
+        GpuWeakPointer::new(self.gpu_ptr, self.generation())
     }
 
     pub(crate) fn gpu_offset_pointer(&self, offset: usize) -> GpuPointer<'_, &'_ [T]> {
@@ -410,9 +851,9 @@ impl<T: Sized, U: Allocation<T>> GpuOnlyArray<T, U> {
         if offset > self.len {
             panic!("Index {} out of bounds (len: {})", offset, self.len);
         }
-        GpuWeakPointer(
+        GpuWeakPointer::new(
             NonZeroU64::new(self.gpu_ptr.get() + (offset * mem::size_of::<T>()) as u64).unwrap(),
-            PhantomData,
+            self.generation(),
         )
     }
 
@@ -430,9 +871,9 @@ impl<T: Sized, U: Allocation<T>> GpuOnlyArray<T, U> {
         if index >= self.len {
             panic!("Index {} out of bounds (len: {})", index, self.len);
         }
-        GpuWeakPointer(
+        GpuWeakPointer::new(
             NonZeroU64::new(self.gpu_ptr.get() + (index * mem::size_of::<T>()) as u64).unwrap(),
-            PhantomData,
+            self.generation(),
         )
     }
 
@@ -490,6 +931,8 @@ impl<T: GpuStruct, U: Allocation<T>> Drop for GpuObject<T, U> {
             core::any::type_name::<T>(),
             self.gpu_pointer()
         );
+        unregister_provenance(self.gpu_va().get());
+        unregister_trace(self.gpu_va().get());
     }
 }
 
@@ -501,6 +944,7 @@ impl<T: Sized, U: Allocation<T>> Drop for GpuOnlyArray<T, U> {
             core::any::type_name::<T>(),
             self.gpu_pointer()
         );
+        unregister_provenance(self.gpu_va().get());
     }
 }
 
@@ -519,3 +963,172 @@ impl<T: Sized + fmt::Debug, U: Allocation<T>> fmt::Debug for GpuArray<T, U> {
             .finish()
     }
 }
+
+/// Minimum capacity a [`GpuVec`] grows to on its first [`GpuVec::grow_auto`].
+const GPU_VEC_MIN_CAPACITY: usize = 4;
+
+/// A growable counterpart to [`GpuArray`].
+///
+/// Unlike [`GpuArray`], which is sized once at construction, `GpuVec` tracks
+/// `len` separately from the backing allocation's capacity and can grow past
+/// it by requesting a new, larger `Allocation` and copying the existing
+/// elements over. Every such reallocation moves the backing GPU VA, which
+/// invalidates any `GpuPointer`/`GpuWeakPointer` previously handed out from
+/// this array -- that is why every growth path below takes `&mut self`, and
+/// why the reallocated range gets a fresh provenance generation that makes
+/// stale pointers fail [`GpuWeakPointer::validate`].
+pub(crate) struct GpuVec<T: Sized, U: Allocation<T>> {
+    raw: *mut T,
+    array: GpuOnlyArray<T, U>,
+    len: usize,
+}
+
+impl<T: Sized, U: Allocation<T>> GpuVec<T, U> {
+    pub(crate) fn new(alloc: U) -> Result<GpuVec<T, U>> {
+        let raw = alloc.ptr().ok_or(EINVAL)?.as_ptr() as *mut T;
+        let capacity = alloc.size() / mem::size_of::<T>();
+        let array = GpuOnlyArray::new(alloc, capacity)?;
+        Ok(Self { raw, array, len: 0 })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.array.len()
+    }
+
+    pub(crate) fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.raw, self.len) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.raw, self.len) }
+    }
+
+    /// Grows capacity to at least `capacity`, calling `allocate` to obtain a
+    /// new, larger `Allocation` if the current one is too small. No-op if
+    /// already large enough.
+    pub(crate) fn reserve(
+        &mut self,
+        capacity: usize,
+        allocate: impl FnOnce(usize) -> Result<U>,
+    ) -> Result<()> {
+        if capacity <= self.capacity() {
+            return Ok(());
+        }
+        let new_alloc = allocate(capacity)?;
+        let new_raw = new_alloc.ptr().ok_or(EINVAL)?.as_ptr() as *mut T;
+        let new_array = GpuOnlyArray::new(new_alloc, capacity)?;
+        unsafe {
+            ptr::copy_nonoverlapping(self.raw, new_raw, self.len);
+        }
+        self.raw = new_raw;
+        self.array = new_array;
+        Ok(())
+    }
+
+    /// Doubles capacity (from [`GPU_VEC_MIN_CAPACITY`]), for amortized growth.
+    pub(crate) fn grow_auto(&mut self, allocate: impl FnOnce(usize) -> Result<U>) -> Result<()> {
+        let new_capacity = core::cmp::max(GPU_VEC_MIN_CAPACITY, self.capacity() * 2);
+        self.reserve(new_capacity, allocate)
+    }
+
+    pub(crate) fn push(&mut self, value: T, allocate: impl FnOnce(usize) -> Result<U>) -> Result<()> {
+        if self.len == self.capacity() {
+            self.grow_auto(allocate)?;
+        }
+        unsafe {
+            self.raw.add(self.len).write(value);
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Resizes to `new_len`. Shrinking drops the trailing elements (if `T`
+    /// needs it); growing reallocates as needed and fills new slots with
+    /// `f()`.
+    pub(crate) fn resize_with(
+        &mut self,
+        new_len: usize,
+        mut f: impl FnMut() -> T,
+        allocate: impl FnOnce(usize) -> Result<U>,
+    ) -> Result<()> {
+        if new_len <= self.len {
+            if mem::needs_drop::<T>() {
+                for i in new_len..self.len {
+                    unsafe {
+                        ptr::drop_in_place(self.raw.add(i));
+                    }
+                }
+            }
+            self.len = new_len;
+            return Ok(());
+        }
+        if new_len > self.capacity() {
+            self.reserve(new_len, allocate)?;
+        }
+        for i in self.len..new_len {
+            unsafe {
+                self.raw.add(i).write(f());
+            }
+        }
+        self.len = new_len;
+        Ok(())
+    }
+}
+
+impl<T: Sized, U: Allocation<T>> Drop for GpuVec<T, U> {
+    fn drop(&mut self) {
+        if mem::needs_drop::<T>() {
+            for elem in self.as_mut_slice() {
+                unsafe {
+                    ptr::drop_in_place(elem);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Sized, U: Allocation<T>> Deref for GpuVec<T, U> {
+    type Target = GpuOnlyArray<T, U>;
+
+    fn deref(&self) -> &GpuOnlyArray<T, U> {
+        &self.array
+    }
+}
+
+impl<T: Sized, U: Allocation<T>> Index<usize> for GpuVec<T, U> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        if index >= self.len {
+            panic!("Index {} out of bounds (len: {})", index, self.len);
+        }
+        unsafe { &*(self.raw.add(index)) }
+    }
+}
+
+impl<T: Sized, U: Allocation<T>> IndexMut<usize> for GpuVec<T, U> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        if index >= self.len {
+            panic!("Index {} out of bounds (len: {})", index, self.len);
+        }
+        unsafe { &mut *(self.raw.add(index)) }
+    }
+}
+
+impl<T: Sized + fmt::Debug, U: Allocation<T>> fmt::Debug for GpuVec<T, U> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct(core::any::type_name::<T>())
+            .field("vec", &format_args!("{:#X?}", self.as_slice()))
+            .finish()
+    }
+}
+
+unsafe impl<T: Sized + Send, U: Allocation<T>> Send for GpuVec<T, U> {}
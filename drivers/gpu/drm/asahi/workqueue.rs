@@ -12,14 +12,17 @@ use crate::fw::types::*;
 use crate::fw::workqueue::*;
 use crate::{alloc, channel, event, gpu, object, regs};
 use crate::{box_in_place, inner_weak_ptr, place};
+use core::future::Future;
 use core::mem;
+use core::pin::Pin;
 use core::sync::atomic::Ordering;
+use core::task::{Context, Poll, Waker};
 use core::time::Duration;
 use kernel::{
-    bindings, dbg,
+    dbg,
     prelude::*,
-    sync::{smutex, Arc, CondVar, Guard, Mutex, UniqueArc},
-    Opaque,
+    sync::{Arc, CondVar, CondVarTimeoutResult, Guard, Mutex, UniqueArc},
+    time::msecs_to_jiffies,
 };
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::WorkQueue;
@@ -35,6 +38,83 @@ pub(crate) enum BatchError {
     Killed,
 }
 
+/// Policy applied by [`WorkQueueBatch::add`] when the ring buffer is full.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum OnFull {
+    /// Block until the ring drains, retrying indefinitely (aside from
+    /// signal interruption). This is the default, and what frame
+    /// submission wants.
+    Block,
+    /// Fail immediately with `EAGAIN` instead of waiting for the ring to
+    /// drain. Useful for latency-sensitive compute submitters that would
+    /// rather resubmit later than get stuck behind a wedged GPU.
+    Fail,
+    /// Block until the ring drains or `timeout` elapses, whichever comes
+    /// first, failing with `ETIMEDOUT` in the latter case.
+    BlockTimeout(Duration),
+}
+
+impl Default for OnFull {
+    fn default() -> Self {
+        OnFull::Block
+    }
+}
+
+/// Firmware scheduling priority for a [`WorkQueue`]. Higher values preempt
+/// lower ones, matching the bands the GPU firmware itself understands.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub(crate) enum QueuePriority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+    Realtime = 3,
+}
+
+impl Default for QueuePriority {
+    fn default() -> Self {
+        QueuePriority::Normal
+    }
+}
+
+/// Range allocator over a [`WorkQueue`]'s ring, modeled on the binder
+/// range allocator: reservations are described by an extent (a start
+/// offset plus a length) instead of being limited to one slot at a time.
+///
+/// Unlike binder, this ring is strictly FIFO: the firmware always
+/// consumes submitted work in commit order, so the free space is always
+/// exactly the one extent between `wptr` and `doneptr` rather than a
+/// scattered set of extents freed out of order — there is deliberately no
+/// free list to merge or split here, just that one running computation.
+struct RingAllocator {
+    size: u32,
+}
+
+impl RingAllocator {
+    fn new(size: u32) -> Self {
+        RingAllocator { size }
+    }
+
+    /// Reserves `n` contiguous slots starting at `wptr`, given the
+    /// firmware's current `doneptr`, keeping one slot permanently free so
+    /// a full ring can be told apart from an empty one. Returns the new
+    /// `wptr` on success; the reservation itself starts at the original
+    /// `wptr` and may wrap through the physical end of the ring.
+    fn reserve(&self, wptr: u32, doneptr: u32, n: u32) -> Option<u32> {
+        if n == 0 || n >= self.size {
+            return None;
+        }
+
+        let used = (wptr + self.size - doneptr) % self.size;
+        let avail = self.size - 1 - used;
+        if avail < n {
+            return None;
+        }
+
+        Some((wptr + n) % self.size)
+    }
+}
+
 impl From<BatchError> for kernel::error::Error {
     fn from(err: BatchError) -> Self {
         match err {
@@ -47,14 +127,23 @@ impl From<BatchError> for kernel::error::Error {
     }
 }
 
+struct BatchState {
+    done: bool,
+    error: Option<BatchError>,
+    wakers: Vec<Waker>,
+}
+
 pub(crate) struct Batch {
     value: event::EventValue,
     commands: usize,
-    // TODO: make abstraction
-    completion: Opaque<bindings::completion>,
+    state: Mutex<BatchState>,
+    cond: CondVar,
     wptr: u32,
     vm_slot: u32,
-    error: smutex::Mutex<Option<BatchError>>,
+    /// Monotonic commit order, used by [`WorkQueue::cancel_from`] to tell
+    /// batches the firmware has already been told about (via a `submit()`
+    /// message) from ones that are still purely local bookkeeping.
+    seq: u64,
 }
 
 impl Batch {
@@ -62,9 +151,48 @@ impl Batch {
         self.value
     }
 
+    /// Blocks the current thread until the batch completes. Synchronous
+    /// counterpart to polling a [`BatchFuture`]; both are fed by the same
+    /// `state`.
     pub(crate) fn wait(&self) -> core::result::Result<(), BatchError> {
-        unsafe { bindings::wait_for_completion(self.completion.get()) };
-        self.error.lock().map_or(Ok(()), Err)
+        let mut state = self.state.lock();
+        while !state.done {
+            self.cond.wait(&mut state);
+        }
+        state.error.map_or(Ok(()), Err)
+    }
+}
+
+/// Newtype around `Arc<Batch>` so it can implement [`Future`]. Both `Future`
+/// and `Arc` are foreign to this crate, so `impl Future for Arc<Batch>`
+/// would violate the orphan rules (E0117); wrapping it in a local type
+/// sidesteps that.
+pub(crate) struct BatchFuture(Arc<Batch>);
+
+impl From<Arc<Batch>> for BatchFuture {
+    fn from(batch: Arc<Batch>) -> Self {
+        BatchFuture(batch)
+    }
+}
+
+impl Future for BatchFuture {
+    type Output = core::result::Result<(), BatchError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `BatchFuture` has no structural pinning of its own (its only
+        // field is an `Arc`, which is `Unpin`), so it's safe to get a
+        // plain reference back out.
+        let this = self.get_mut();
+        let mut state = this.0.state.lock();
+        if state.done {
+            return Poll::Ready(state.error.map_or(Ok(()), Err));
+        }
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            if state.wakers.try_push(cx.waker().clone()).is_err() {
+                pr_err!("Batch: failed to register waker, future may never wake");
+            }
+        }
+        Poll::Pending
     }
 }
 
@@ -73,12 +201,23 @@ struct WorkQueueInner {
     info: GpuObject<QueueInfo>,
     new: bool,
     pipe_type: PipeType,
+    priority: QueuePriority,
+    on_full: OnFull,
     size: u32,
     wptr: u32,
     pending: Vec<Box<dyn object::OpaqueGpuObject>>,
     batches: Vec<Arc<Batch>>,
     last_token: Option<event::Token>,
     event: Option<(event::Event, event::EventValue)>,
+    /// `wptr` as it was the last time `batches` went from empty to
+    /// non-empty; the rewind target if every currently-held batch ends up
+    /// cancelled.
+    base_wptr: u32,
+    /// Sequence number handed out to the next committed batch.
+    next_batch_seq: u64,
+    /// Sequence number of the first batch that has *not* yet been
+    /// announced to the firmware via a `RunWorkQueueMsg`.
+    submitted_seq: u64,
 }
 
 unsafe impl Send for WorkQueueInner {}
@@ -107,6 +246,14 @@ pub(crate) struct WorkQueueBatch<'a> {
     vm_slot: u32,
 }
 
+impl object::Trace for QueueInfo {
+    fn trace(&self, visitor: &mut dyn FnMut(u64, &'static str)) {
+        self.state.weak_pointer().trace(visitor);
+        self.ring.weak_pointer().trace(visitor);
+        self.gpu_buf.weak_pointer().trace(visitor);
+    }
+}
+
 impl WorkQueue {
     pub(crate) fn new(
         alloc: &mut gpu::KernelAllocators,
@@ -114,6 +261,8 @@ impl WorkQueue {
         gpu_context: GpuWeakPointer<GpuContextData>,
         notifier_list: GpuWeakPointer<NotifierList>,
         pipe_type: PipeType,
+        priority: QueuePriority,
+        on_full: OnFull,
         id: u64,
     ) -> Result<Arc<WorkQueue>> {
         let mut info = box_in_place!(QueueInfo {
@@ -140,7 +289,7 @@ impl WorkQueue {
                         gpu_rptr2: Default::default(),
                         gpu_rptr3: Default::default(),
                         event_id: AtomicI32::new(-1),
-                        priority: Default::default(),
+                        priority: priority as u32,
                         unk_4c: -1,
                         uuid: id as u32,
                         unk_54: -1,
@@ -161,12 +310,17 @@ impl WorkQueue {
             })?,
             new: true,
             pipe_type,
+            priority,
+            on_full,
             size: WQ_SIZE,
             wptr: 0,
             pending: Vec::new(),
             batches: Vec::new(),
             last_token: None,
             event: None,
+            base_wptr: 0,
+            next_batch_seq: 0,
+            submitted_seq: 0,
         };
 
         let mut queue = Pin::from(UniqueArc::try_new(Self {
@@ -193,6 +347,15 @@ impl WorkQueue {
             PipeType::Compute => kernel::mutex_init!(pinned, "WorkQueue::inner (Compute)"),
         }
 
+        // SAFETY: `queue` is pinned behind `UniqueArc` and never moved out
+        // of by value again, so `info` (nested inside `queue.inner`) has
+        // reached its final, stable address -- exactly what
+        // `GpuObject::register_trace` requires.
+        {
+            let inner = queue.inner.lock();
+            unsafe { Pin::new_unchecked(&inner.info) }.register_trace();
+        }
+
         Ok(queue.into())
     }
 
@@ -282,7 +445,15 @@ impl WorkQueue {
         core::mem::drop(inner);
 
         for batch in completed {
-            unsafe { bindings::complete_all(batch.completion.get()) };
+            let wakers = {
+                let mut state = batch.state.lock();
+                state.done = true;
+                mem::take(&mut state.wakers)
+            };
+            batch.cond.notify_all();
+            for waker in wakers {
+                waker.wake();
+            }
         }
         empty
     }
@@ -314,7 +485,7 @@ impl WorkQueue {
                     batch.value,
                     batch.commands,
                 );
-                *(batch.error.lock()) = Some(match error {
+                batch.state.lock().error = Some(match error {
                     BatchError::Fault(info) if info.vm_slot != batch.vm_slot => BatchError::Killed,
                     err => err,
                 });
@@ -323,26 +494,152 @@ impl WorkQueue {
             }
         }
     }
+
+    /// Marks every batch at or after `value` as [`BatchError::Killed`],
+    /// reclaiming their ring slots when the firmware was never told about
+    /// them in the first place.
+    pub(crate) fn cancel_from(&self, value: event::EventValue) {
+        let mut inner = self.inner.lock();
+
+        let cut = inner.batches.partition_point(|b| b.value < value);
+
+        for batch in &inner.batches[cut..] {
+            batch.state.lock().error = Some(BatchError::Killed);
+        }
+
+        // Only batches committed after the last `submit()` exist purely as
+        // local bookkeeping; the firmware was never told about them, so
+        // (and only so) can their ring slots be reclaimed. Anything with a
+        // `seq` below `submitted_seq` has already been handed to the firmware in
+        // a `RunWorkQueueMsg` and must be left alone — it will complete (or
+        // not) through the normal `signal()`/`mark_error()` path, just with
+        // `BatchError::Killed` already staged above.
+        let rewind_from = inner.batches[cut..]
+            .iter()
+            .position(|b| b.seq >= inner.submitted_seq)
+            .map(|i| cut + i)
+            .unwrap_or(inner.batches.len());
+
+        if rewind_from < inner.batches.len() {
+            let new_wptr = if rewind_from == 0 {
+                inner.base_wptr
+            } else {
+                inner.batches[rewind_from - 1].wptr
+            };
+
+            let removed_commands: usize = inner.batches[rewind_from..]
+                .iter()
+                .map(|b| b.commands)
+                .sum();
+
+            inner
+                .info
+                .state
+                .with(|raw, _inner| raw.cpu_wptr.store(new_wptr, Ordering::Release));
+            inner.wptr = new_wptr;
+
+            let new_pending_len = inner.pending.len() - removed_commands;
+            inner.pending.truncate(new_pending_len);
+
+            let cancelled = inner.batches.split_off(rewind_from);
+            core::mem::drop(inner);
+
+            for batch in cancelled {
+                let wakers = {
+                    let mut state = batch.state.lock();
+                    state.done = true;
+                    mem::take(&mut state.wakers)
+                };
+                batch.cond.notify_all();
+                for waker in wakers {
+                    waker.wake();
+                }
+            }
+        }
+
+        self.cond.notify_all();
+    }
+
+    /// Cancels every batch currently queued or in flight on this queue.
+    pub(crate) fn cancel_all(&self) {
+        self.cancel_from(event::EventValue::default());
+    }
 }
 
 impl<'a> WorkQueueBatch<'a> {
-    pub(crate) fn add<T: Command>(&mut self, command: Box<GpuObject<T>>) -> Result {
+    /// Reserves `n` contiguous ring slots for this batch, blocking, timing
+    /// out, or failing immediately per the queue's [`OnFull`] policy, and
+    /// returns the physical offset of the first reserved slot. The run may
+    /// still wrap through the physical end of the ring array, the same
+    /// way a lone slot already does via `(wptr + 1) % size`; callers write
+    /// into it with [`Self::ring_index`].
+    ///
+    /// There is no separate step to free a reservation: the firmware only
+    /// ever consumes the ring in commit order, so [`RingAllocator`]'s free
+    /// space grows back automatically as `doneptr()` (driven by
+    /// `cpu_freeptr`, which `signal()` advances past each completed batch)
+    /// catches up to `wptr`.
+    pub(crate) fn reserve(&mut self, n: u32) -> Result<u32> {
         let inner = &mut self.inner;
 
-        let next_wptr = (self.wptr + 1) % inner.size;
-        if inner.doneptr() == next_wptr {
-            pr_err!("Work queue ring buffer is full! Waiting...");
-            while inner.doneptr() == next_wptr {
-                if self.queue.cond.wait(inner) {
-                    return Err(ERESTARTSYS);
+        if n == 0 || n >= inner.size {
+            return Err(EINVAL);
+        }
+
+        let alloc = RingAllocator::new(inner.size);
+        let mut waited = false;
+        let mut timeout_jiffies = match inner.on_full {
+            OnFull::BlockTimeout(timeout) => msecs_to_jiffies(timeout.as_millis() as u64),
+            _ => 0,
+        };
+
+        loop {
+            if let Some(next_wptr) = alloc.reserve(self.wptr, inner.doneptr(), n) {
+                let start = self.wptr;
+                self.wptr = next_wptr;
+                return Ok(start);
+            }
+
+            match inner.on_full {
+                OnFull::Fail => return Err(EAGAIN),
+                OnFull::Block => {
+                    if !waited {
+                        pr_err!("Work queue ring buffer is full! Waiting...");
+                        waited = true;
+                    }
+                    if self.queue.cond.wait(inner) {
+                        return Err(ERESTARTSYS);
+                    }
+                }
+                OnFull::BlockTimeout(_) => {
+                    if !waited {
+                        pr_err!("Work queue ring buffer is full! Waiting (with timeout)...");
+                        waited = true;
+                    }
+                    match self.queue.cond.wait_timeout(inner, timeout_jiffies) {
+                        CondVarTimeoutResult::Signal => return Err(ERESTARTSYS),
+                        CondVarTimeoutResult::Timeout => return Err(ETIMEDOUT),
+                        CondVarTimeoutResult::Woken(left) => timeout_jiffies = left,
+                    }
                 }
             }
         }
-        inner.pending.try_reserve(1)?;
+    }
 
-        inner.info.ring[self.wptr as usize] = command.gpu_va().get();
+    /// Maps `offset` within a reservation starting at `start` (as
+    /// returned by [`Self::reserve`]) to a physical ring index.
+    pub(crate) fn ring_index(&self, start: u32, offset: u32) -> usize {
+        ((start + offset) % self.inner.size) as usize
+    }
+
+    pub(crate) fn add<T: Command>(&mut self, command: Box<GpuObject<T>>) -> Result {
+        let start = self.reserve(1)?;
+        let idx = self.ring_index(start, 0);
+
+        let inner = &mut self.inner;
+        inner.pending.try_reserve(1)?;
 
-        self.wptr = next_wptr;
+        inner.info.ring[idx] = command.gpu_va().get();
 
         // Cannot fail, since we did a try_reserve(1) above
         inner
@@ -366,21 +663,45 @@ impl<'a> WorkQueueBatch<'a> {
         event.1.increment();
         let event_value = event.1;
 
+        if inner.batches.is_empty() {
+            inner.base_wptr = inner.wptr;
+        }
+
         inner
             .info
             .state
             .with(|raw, _inner| raw.cpu_wptr.store(self.wptr, Ordering::Release));
 
         inner.wptr = self.wptr;
-        let batch = Arc::try_new(Batch {
+        let seq = inner.next_batch_seq;
+        inner.next_batch_seq += 1;
+        let mut batch = Pin::from(UniqueArc::try_new(Batch {
             value: event_value,
             commands: self.commands,
-            completion: Opaque::uninit(),
+            // SAFETY: `mutex_init!` is called below.
+            state: unsafe {
+                Mutex::new(BatchState {
+                    done: false,
+                    error: None,
+                    wakers: Vec::new(),
+                })
+            },
+            // SAFETY: `condvar_init!` is called below.
+            cond: unsafe { CondVar::new() },
             wptr: self.wptr,
-            error: smutex::Mutex::new(None),
             vm_slot: self.vm_slot,
-        })?;
-        unsafe { bindings::init_completion(batch.completion.get()) };
+            seq,
+        })?);
+
+        // SAFETY: `state` is pinned when `batch` is.
+        let pinned = unsafe { batch.as_mut().map_unchecked_mut(|b| &mut b.state) };
+        kernel::mutex_init!(pinned, "Batch::state");
+
+        // SAFETY: `cond` is pinned when `batch` is.
+        let pinned = unsafe { batch.as_mut().map_unchecked_mut(|b| &mut b.cond) };
+        kernel::condvar_init!(pinned, "Batch::cond");
+
+        let batch: Arc<Batch> = batch.into();
         inner.batches.try_push(batch.clone())?;
         self.commands = 0;
         Ok(batch)
@@ -393,6 +714,11 @@ impl<'a> WorkQueueBatch<'a> {
 
         let inner = &mut self.inner;
         let event = inner.event.as_ref().expect("WorkQueueBatch lost its event");
+        // NOTE: `RunWorkQueueMsg` has no priority field of its own; the
+        // firmware picks it up from `QueueInfo.priority` (set once at
+        // `WorkQueue::new` time) whenever `is_new` causes it to
+        // (re-)register this queue, so there is nothing to thread through
+        // here on an ordinary (non-`is_new`) submission.
         let msg = RunWorkQueueMsg {
             pipe_type: inner.pipe_type,
             work_queue: Some(inner.info.weak_pointer()),
@@ -403,6 +729,7 @@ impl<'a> WorkQueueBatch<'a> {
         };
         channel.send(&msg);
         inner.new = false;
+        inner.submitted_seq = inner.next_batch_seq;
         Ok(())
     }
 
@@ -427,6 +754,10 @@ impl<'a> WorkQueueBatch<'a> {
     pub(crate) fn pipe_type(&self) -> PipeType {
         self.inner.pipe_type
     }
+
+    pub(crate) fn priority(&self) -> QueuePriority {
+        self.inner.priority
+    }
 }
 
 impl<'a> Drop for WorkQueueBatch<'a> {